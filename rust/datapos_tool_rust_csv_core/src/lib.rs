@@ -3,14 +3,265 @@
 //! This module wraps the `csv-core` parser so the browser can feed raw
 //! bytes from a `ReadableStream` into WebAssembly and receive parsed rows.
 
-use csv_core::{ReadRecordResult, Reader, ReaderBuilder};
+use csv_core::{ReadRecordResult, Reader, ReaderBuilder, Terminator};
 use futures::StreamExt;
 use js_sys::{Function, Uint8Array};
+use serde::{Deserialize, Serialize};
 use serde_wasm_bindgen::to_value;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::future_to_promise;
 use wasm_streams::ReadableStream as WasmReadableStream;
 
+/// Configuration forwarded to the underlying `csv-core` `ReaderBuilder`.
+///
+/// Callers build this from a plain JS object (`{ delimiter, quote, escape,
+/// terminator, flexible, inferTypes, sampleSize, batchSize, keyedRows,
+/// lossy }`) via `serde_wasm_bindgen`, so every field besides `delimiter` is
+/// optional and falls back to `csv-core`'s own defaults (or, for
+/// `inferTypes`/`sampleSize`/`batchSize`/`keyedRows`/`lossy`, to emitting
+/// plain strings in one unbounded batch of positional arrays, aborting on
+/// the first invalid UTF-8 field).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CsvOptions {
+    /// Byte that separates fields within a record.
+    pub delimiter: u8,
+    /// Byte used to quote fields containing delimiters or newlines.
+    #[serde(default = "default_quote")]
+    pub quote: u8,
+    /// Escape byte for a quote character appearing inside a quoted field.
+    #[serde(default)]
+    pub escape: Option<u8>,
+    /// Custom record terminator byte; `None` keeps `csv-core`'s CRLF handling.
+    #[serde(default)]
+    pub terminator: Option<u8>,
+    /// Whether records may have a varying number of fields.
+    ///
+    /// `csv-core`'s byte-level reader has no concept of record-level
+    /// validation (that's a `csv`-crate feature built on cross-record
+    /// state), so this isn't forwarded to the reader at all; `CsvSession`
+    /// enforces it itself by comparing each row's field count against the
+    /// header (or the first row's width, when there is no header).
+    #[serde(default)]
+    pub flexible: bool,
+    /// Whether to classify columns and emit typed values instead of strings.
+    #[serde(default)]
+    pub infer_types: bool,
+    /// Number of rows sampled before a column's inferred type is frozen.
+    #[serde(default = "default_sample_size")]
+    pub sample_size: usize,
+    /// Maximum rows returned per batch; `0` means no cap.
+    #[serde(default)]
+    pub batch_size: usize,
+    /// Emit rows as header-keyed objects instead of positional arrays.
+    /// Ignored when `has_headers` is `false`, since there are no keys.
+    #[serde(default)]
+    pub keyed_rows: bool,
+    /// Decode invalid UTF-8 fields with `String::from_utf8_lossy` instead of
+    /// aborting the stream; the row is still emitted, with its decoding
+    /// errors attached.
+    #[serde(default)]
+    pub lossy: bool,
+}
+
+fn default_quote() -> u8 {
+    b'"'
+}
+
+fn default_sample_size() -> usize {
+    1000
+}
+
+impl CsvOptions {
+    /// Build a `csv-core` reader configured from these options.
+    fn build_reader(&self) -> Reader {
+        let terminator = match self.terminator {
+            Some(byte) => Terminator::Any(byte),
+            None => Terminator::CRLF,
+        };
+
+        ReaderBuilder::new()
+            .delimiter(self.delimiter)
+            .quote(self.quote)
+            .escape(self.escape)
+            .terminator(terminator)
+            .build()
+    }
+
+    fn from_js(options: JsValue) -> Result<CsvOptions, JsValue> {
+        serde_wasm_bindgen::from_value(options)
+            .map_err(|error| JsValue::from_str(&format!("invalid CSV options: {error}")))
+    }
+}
+
+/// The type a column is currently believed to hold, narrowest first.
+///
+/// Columns start at `Boolean` and widen whenever a sampled field fails to
+/// parse as the current candidate, mirroring how arrow-rs's CSV reader
+/// narrows then widens per-column types while sampling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CandidateType {
+    Boolean,
+    Int64,
+    Float64,
+    Date,
+    Utf8,
+}
+
+const CANDIDATE_WIDENING_ORDER: [CandidateType; 5] = [
+    CandidateType::Boolean,
+    CandidateType::Int64,
+    CandidateType::Float64,
+    CandidateType::Date,
+    CandidateType::Utf8,
+];
+
+/// A single cell value as handed to JavaScript once type inference runs.
+///
+/// `Number` covers both integer and floating-point columns since JS has a
+/// single numeric type; `Date` columns are passed through as ISO strings
+/// rather than constructed `Date` objects so callers can parse them however
+/// they like.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum CellValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    Text(String),
+}
+
+/// Try to read `field` as `candidate`, returning `None` if it doesn't fit.
+fn try_parse_as(candidate: CandidateType, field: &str) -> Option<CellValue> {
+    match candidate {
+        CandidateType::Boolean => match field {
+            "true" | "True" | "TRUE" => Some(CellValue::Bool(true)),
+            "false" | "False" | "FALSE" => Some(CellValue::Bool(false)),
+            _ => None,
+        },
+        CandidateType::Int64 => field.parse::<i64>().ok().map(|v| CellValue::Number(v as f64)),
+        CandidateType::Float64 => field.parse::<f64>().ok().map(CellValue::Number),
+        CandidateType::Date => looks_like_iso_date(field).then(|| CellValue::Text(field.to_string())),
+        CandidateType::Utf8 => Some(CellValue::Text(field.to_string())),
+    }
+}
+
+/// A loose `YYYY-MM-DD` check, good enough to widen a column to `Date`
+/// without pulling in a date-parsing dependency.
+fn looks_like_iso_date(field: &str) -> bool {
+    let bytes = field.as_bytes();
+    bytes.len() >= 10
+        && bytes[0..4].iter().all(u8::is_ascii_digit)
+        && bytes[4] == b'-'
+        && bytes[5..7].iter().all(u8::is_ascii_digit)
+        && bytes[7] == b'-'
+        && bytes[8..10].iter().all(u8::is_ascii_digit)
+}
+
+/// Classify `field` against `candidate` while sampling is still open,
+/// widening `candidate` in place when the narrower type no longer fits.
+fn classify_and_widen(candidate: &mut CandidateType, field: &str) -> CellValue {
+    if field.is_empty() {
+        return CellValue::Null;
+    }
+
+    let start = CANDIDATE_WIDENING_ORDER
+        .iter()
+        .position(|ty| *ty == *candidate)
+        .unwrap_or(0);
+
+    for &ty in &CANDIDATE_WIDENING_ORDER[start..] {
+        if let Some(value) = try_parse_as(ty, field) {
+            *candidate = ty;
+            return value;
+        }
+    }
+
+    // `Utf8` always matches above, so this is unreachable, but keep a safe
+    // fallback rather than panicking on unexpected input.
+    *candidate = CandidateType::Utf8;
+    CellValue::Text(field.to_string())
+}
+
+/// Coerce `field` to an already-frozen column type, falling back to the raw
+/// string for this cell alone when the value no longer fits.
+fn coerce_to_frozen(candidate: CandidateType, field: &str) -> CellValue {
+    if field.is_empty() {
+        return CellValue::Null;
+    }
+
+    try_parse_as(candidate, field).unwrap_or_else(|| CellValue::Text(field.to_string()))
+}
+
+/// Whether the sample window has seen enough rows to freeze column types.
+fn sample_window_closed(rows_sampled: usize, sample_size: usize) -> bool {
+    rows_sampled >= sample_size
+}
+
+/// A CSV row rendered as a header-keyed JS object instead of a positional
+/// array. Entries are kept in header order (with any extra fields appended)
+/// rather than in a `HashMap`, so callers see fields in file order.
+struct KeyedRow(Vec<(String, CellValue)>);
+
+impl Serialize for KeyedRow {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeMap;
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (key, value) in &self.0 {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+/// A row in whichever shape the caller asked for: a positional array, or a
+/// header-keyed object once `keyed_rows` and headers are both available.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum RowOutput {
+    Positional(Vec<CellValue>),
+    Keyed(KeyedRow),
+}
+
+/// A structured decoding error, positioned by record (following the
+/// position tracking rust-csv added) plus an approximate byte offset of the
+/// record within the stream.
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ParseError {
+    kind: &'static str,
+    record: usize,
+    /// Index of the offending field. Set for field-level errors like
+    /// `utf8`; unset for record-level errors like `field_count`, which
+    /// aren't about any one field.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<usize>,
+    byte_offset: u64,
+    message: String,
+    /// Present only on `field_count` errors: the row's expected and actual
+    /// field counts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expected_fields: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    found_fields: Option<usize>,
+}
+
+/// A row plus, when `lossy` decoding had to patch it up, the errors it hit.
+/// Rows without errors serialise exactly as `RowOutput` would on its own, so
+/// turning `lossy` on doesn't change the shape of already-clean rows.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum RowResult {
+    Clean(RowOutput),
+    WithErrors {
+        row: RowOutput,
+        errors: Vec<ParseError>,
+    },
+}
+
 #[wasm_bindgen]
 pub fn init() {
     // Install better panic messages so JavaScript receives meaningful errors.
@@ -37,16 +288,63 @@ pub struct CsvSession {
     headers_skipped: bool,
     /// Cached normalized header names.
     normalized_headers: Option<Vec<String>>,
+    /// Whether to classify columns and emit typed values instead of strings.
+    infer_types: bool,
+    /// Number of data rows to sample before freezing column types.
+    sample_size: usize,
+    /// Number of data rows sampled so far, while types are not yet frozen.
+    rows_sampled: usize,
+    /// Whether the sample window has closed and column types are frozen.
+    types_frozen: bool,
+    /// Per-column inferred type, grown lazily as wider rows are seen.
+    column_types: Vec<CandidateType>,
+    /// Maximum rows returned per batch; `0` means no cap (one big batch).
+    batch_size: usize,
+    /// Whether `finish` has been called, so later batches keep flushing.
+    finishing: bool,
+    /// Whether the last drain stopped early because it hit `batch_size`,
+    /// meaning more rows are ready without needing another `pushChunk`.
+    more_pending: bool,
+    /// Staging region JS can write chunk bytes into directly via `alloc`,
+    /// avoiding the `Uint8Array` copy that `pushChunk` still pays for.
+    /// `pushLen` still copies out of this region into `buffer` below, so
+    /// this removes only that one JS-side copy, not every copy in the path.
+    staging: Vec<u8>,
+    /// Whether to emit rows as header-keyed objects instead of arrays.
+    keyed_rows: bool,
+    /// Whether records may have a varying number of fields, enforced by
+    /// comparing each row's width against `expected_field_count`.
+    flexible: bool,
+    /// Field count every row is expected to match once `flexible` is
+    /// `false`; set from the header row, or the first data row when there
+    /// is no header.
+    expected_field_count: Option<usize>,
+    /// Whether to decode invalid UTF-8 lossily instead of aborting.
+    lossy: bool,
+    /// Count of `csv-core` records read so far, including the header row;
+    /// used to position parse errors.
+    record_index: usize,
+    /// Total input bytes consumed so far; used as an approximate byte
+    /// offset for parse errors, since `csv-core` re-writes field bytes into
+    /// `record_buffer` and doesn't preserve their original offsets.
+    bytes_consumed: u64,
 }
 
 #[wasm_bindgen]
 impl CsvSession {
     #[wasm_bindgen(constructor)]
-    pub fn new(delimiter: u8, has_headers: bool) -> CsvSession {
-        // Configure the CSV reader with the delimiter chosen by the caller.
-        let reader = ReaderBuilder::new().delimiter(delimiter).build();
+    pub fn new(options: JsValue, has_headers: bool) -> Result<CsvSession, JsValue> {
+        // Configure the CSV reader from the caller-supplied options object.
+        let options = CsvOptions::from_js(options)?;
+        let infer_types = options.infer_types;
+        let sample_size = options.sample_size;
+        let batch_size = options.batch_size;
+        let keyed_rows = options.keyed_rows;
+        let lossy = options.lossy;
+        let flexible = options.flexible;
+        let reader = options.build_reader();
 
-        CsvSession {
+        Ok(CsvSession {
             reader,
             buffer: Vec::new(),
             record_buffer: vec![0; 1024],
@@ -56,7 +354,63 @@ impl CsvSession {
             has_headers,
             headers_skipped: false,
             normalized_headers: None,
+            infer_types,
+            sample_size,
+            rows_sampled: 0,
+            types_frozen: false,
+            column_types: Vec::new(),
+            batch_size,
+            finishing: false,
+            more_pending: false,
+            staging: Vec::new(),
+            keyed_rows,
+            flexible,
+            expected_field_count: None,
+            lossy,
+            record_index: 0,
+            bytes_consumed: 0,
+        })
+    }
+
+    /// Reserve `len` bytes of session-owned memory for JS to write the next
+    /// chunk into directly, then hand back a pointer into it. The region is
+    /// reused (only grown, never freed) across calls so repeated streaming
+    /// pushes don't reallocate.
+    #[wasm_bindgen]
+    pub fn alloc(&mut self, len: usize) -> *mut u8 {
+        if self.staging.len() < len {
+            self.staging.resize(len, 0);
+        }
+        self.staging.as_mut_ptr()
+    }
+
+    /// Consume the first `len` bytes JS wrote into the region returned by
+    /// the most recent `alloc` call on this session, parsing them exactly
+    /// like `pushChunk` would. This still copies those bytes into `buffer`
+    /// before parsing; what it removes is the `Uint8Array::copy_to` hop
+    /// `pushChunk` needs to get bytes out of JS in the first place, not the
+    /// copy into `buffer` itself.
+    #[wasm_bindgen(js_name = pushLen)]
+    pub fn push_len(&mut self, ptr: *const u8, len: usize) -> Result<JsValue, JsValue> {
+        if ptr != self.staging.as_ptr() {
+            return Err(JsValue::from_str(
+                "pushLen called with a pointer not returned by this session's alloc",
+            ));
+        }
+        if len > self.staging.len() {
+            return Err(JsValue::from_str("pushLen length exceeds the allocated region"));
         }
+
+        self.buffer.extend_from_slice(&self.staging[..len]);
+        let records = self.drain_records(false)?;
+        rows_to_js_value(records)
+    }
+
+    /// Release the staging region's backing memory. Safe to call between
+    /// streams; the next `alloc` simply reallocates on demand.
+    #[wasm_bindgen]
+    pub fn free(&mut self) {
+        self.staging = Vec::new();
     }
 
     #[wasm_bindgen(js_name = pushChunk)]
@@ -71,34 +425,57 @@ impl CsvSession {
 
     #[wasm_bindgen]
     pub fn finish(&mut self) -> Result<JsValue, JsValue> {
-        // Flush all pending bytes and return any remaining rows.
+        // Flush pending bytes and return the first batch of remaining rows.
+        // If more rows than `batch_size` were ready, `hasMore` stays true and
+        // callers keep pulling with `nextBatch`.
         let records = self.finish_rows()?;
         rows_to_js_value(records)
     }
 
-    fn push_bytes(&mut self, data: &[u8]) -> Result<Vec<Vec<String>>, JsValue> {
-        // Append the new bytes and attempt to extract complete records.
+    /// Whether another batch is ready without needing more input bytes.
+    #[wasm_bindgen(js_name = hasMore)]
+    pub fn has_more(&self) -> bool {
+        self.more_pending
+    }
+
+    /// Pull the next capped batch of rows from already-buffered bytes.
+    #[wasm_bindgen(js_name = nextBatch)]
+    pub fn next_batch(&mut self) -> Result<JsValue, JsValue> {
+        let records = self.next_batch_rows()?;
+        rows_to_js_value(records)
+    }
+
+    fn next_batch_rows(&mut self) -> Result<Vec<RowResult>, JsValue> {
+        self.drain_records(self.finishing)
+    }
+
+    fn push_bytes(&mut self, data: &[u8]) -> Result<Vec<RowResult>, JsValue> {
+        // Append the new bytes and attempt to extract up to one batch worth
+        // of complete records; any remainder stays buffered for `nextBatch`.
         self.buffer.extend_from_slice(data);
         self.drain_records(false)
     }
 
-    fn finish_rows(&mut self) -> Result<Vec<Vec<String>>, JsValue> {
+    fn finish_rows(&mut self) -> Result<Vec<RowResult>, JsValue> {
         // The `csv-core` reader expects newline-terminated input. Append a
         // newline when the data source does not end with one.
         if !self.buffer.is_empty() && !self.buffer.ends_with(b"\n") {
             self.buffer.push(b'\n');
         }
 
+        self.finishing = true;
         self.drain_records(true)
     }
 
-    fn drain_records(&mut self, final_flush: bool) -> Result<Vec<Vec<String>>, JsValue> {
-        // `output` collects the fully parsed rows for this call. We re-use the
-        // `pending_*` vectors so partially read records survive across pushes.
-        let mut output: Vec<Vec<String>> = Vec::new();
+    fn drain_records(&mut self, final_flush: bool) -> Result<Vec<RowResult>, JsValue> {
+        // `output` collects up to `batch_size` rows for this call (all of
+        // them when `batch_size` is `0`). We re-use the `pending_*` vectors
+        // so partially read records survive across pushes.
+        let mut output: Vec<RowResult> = Vec::new();
         let mut offset: usize = 0;
         let mut current_record = core::mem::take(&mut self.pending_record);
         let mut current_field_ends = core::mem::take(&mut self.pending_field_ends);
+        let mut hit_batch_limit = false;
 
         while offset < self.buffer.len() {
             let input = &self.buffer[offset..];
@@ -107,6 +484,7 @@ impl CsvSession {
                     .read_record(input, &mut self.record_buffer, &mut self.field_ends);
 
             offset = offset.saturating_add(in_read);
+            self.bytes_consumed = self.bytes_consumed.saturating_add(in_read as u64);
 
             // Accumulate record bytes and field markers until we have a full row.
             if out_written > 0 {
@@ -118,25 +496,125 @@ impl CsvSession {
 
             match result {
                 ReadRecordResult::Record => {
-                    // We reached the end of a row. Materialise it as UTF-8 strings.
-                    let row = build_row(&current_record, &current_field_ends)?;
-                    if self.has_headers && !self.headers_skipped {
-                        let normalized = row
-                            .iter()
-                            .map(|field| normalize_field_name(field))
-                            .collect();
-                        self.normalized_headers = Some(normalized);
-                        // Discard the first row when headers are enabled.
-                        self.headers_skipped = true;
-                    } else {
-                        let summary = summarize_row(&row);
-                        if !summary.is_empty() {
-                            output.push(row);
+                    // A fatal error aborts the whole call, but the session's
+                    // own bookkeeping (buffer position, pending record) must
+                    // still be left consistent first — this record is fully
+                    // read, so there is nothing left pending for it, and the
+                    // bytes already consumed for it should not be re-read.
+                    // Every fatal path below (decode failure, header
+                    // failure, field-count mismatch) funnels through this
+                    // one variable so it gets that cleanup instead of
+                    // bypassing it via an early `?` return.
+                    let mut fatal_error: Option<JsValue> = None;
+
+                    // Materialise the record as UTF-8 strings. A non-lossy
+                    // decode failure is itself fatal, so there is no row to
+                    // process this iteration.
+                    match build_row(
+                        &current_record,
+                        &current_field_ends,
+                        self.record_index,
+                        self.bytes_consumed,
+                        self.lossy,
+                    ) {
+                        Err(error) => {
+                            fatal_error = Some(
+                                to_value(&error).unwrap_or_else(|_| JsValue::from_str(&error.message)),
+                            );
+                        }
+                        Ok((row, row_errors)) => {
+                            let this_record_index = self.record_index;
+                            self.record_index += 1;
+
+                            if self.has_headers && !self.headers_skipped {
+                                // The header row defines every later row's keys, so a
+                                // decode error here can't be patched up lossily and
+                                // silently dropped the way a data row's can — surface
+                                // it and abort even when `lossy` is set.
+                                if let Some(error) = row_errors.into_iter().next() {
+                                    fatal_error = Some(to_value(&error)
+                                        .unwrap_or_else(|_| JsValue::from_str(&error.message)));
+                                } else {
+                                    let normalized = dedupe_header_names(
+                                        row.iter().map(|field| normalize_field_name(field)).collect(),
+                                    );
+                                    self.expected_field_count = Some(normalized.len());
+                                    self.normalized_headers = Some(normalized);
+                                    // Discard the first row when headers are enabled.
+                                    self.headers_skipped = true;
+                                }
+                            } else {
+                                let summary = summarize_row(&row);
+                                if !summary.is_empty() {
+                                    let field_count_ok = if self.flexible {
+                                        true
+                                    } else {
+                                        match self.expected_field_count {
+                                            Some(expected) if expected != row.len() => {
+                                                let parse_error = ParseError {
+                                                    kind: "field_count",
+                                                    record: this_record_index,
+                                                    field: None,
+                                                    byte_offset: self.bytes_consumed,
+                                                    message: format!(
+                                                        "record has {} field(s), expected {} (set `flexible: true` to allow ragged rows)",
+                                                        row.len(),
+                                                        expected
+                                                    ),
+                                                    expected_fields: Some(expected),
+                                                    found_fields: Some(row.len()),
+                                                };
+                                                fatal_error = Some(
+                                                    to_value(&parse_error)
+                                                        .unwrap_or_else(|_| JsValue::from_str(&parse_error.message)),
+                                                );
+                                                false
+                                            }
+                                            Some(_) => true,
+                                            None => {
+                                                self.expected_field_count = Some(row.len());
+                                                true
+                                            }
+                                        }
+                                    };
+
+                                    if field_count_ok {
+                                        let cells = self.classify_row(row);
+                                        let row_output = self.to_row_output(cells);
+                                        let result = if row_errors.is_empty() {
+                                            RowResult::Clean(row_output)
+                                        } else {
+                                            RowResult::WithErrors {
+                                                row: row_output,
+                                                errors: row_errors,
+                                            }
+                                        };
+                                        output.push(result);
+                                        if self.batch_size > 0 && output.len() >= self.batch_size {
+                                            hit_batch_limit = true;
+                                        }
+                                    }
+                                }
+                            }
                         }
                     }
                     // Reset our scratch buffers for the next record.
                     current_record.clear();
                     current_field_ends.clear();
+
+                    if let Some(error) = fatal_error {
+                        if offset > 0 {
+                            self.buffer.drain(..offset);
+                        }
+                        self.more_pending = false;
+                        self.pending_record = current_record;
+                        self.pending_field_ends = current_field_ends;
+                        return Err(error);
+                    }
+
+                    if hit_batch_limit {
+                        break;
+                    }
                 }
                 ReadRecordResult::InputEmpty => break,
                 ReadRecordResult::OutputFull => {
@@ -158,16 +636,78 @@ impl CsvSession {
             self.buffer.drain(..offset);
         }
 
-        if final_flush {
-            // We are finishing the stream, so the buffer can be cleared.
+        if final_flush && !hit_batch_limit {
+            // We are finishing the stream and drained everything ready, so
+            // the buffer can be cleared. If we stopped early for the batch
+            // cap, leftover complete records must survive for `nextBatch`.
             self.buffer.clear();
         }
 
+        self.more_pending = hit_batch_limit;
         self.pending_record = current_record;
         self.pending_field_ends = current_field_ends;
 
         Ok(output)
     }
+
+    /// Turn a raw string row into typed cells, widening or coercing the
+    /// per-column candidate types as the sample window requires.
+    fn classify_row(&mut self, row: Vec<String>) -> Vec<CellValue> {
+        if !self.infer_types {
+            return row.into_iter().map(CellValue::Text).collect();
+        }
+
+        if self.column_types.len() < row.len() {
+            self.column_types
+                .resize(row.len(), CandidateType::Boolean);
+        }
+
+        let cells = row
+            .iter()
+            .enumerate()
+            .map(|(index, field)| {
+                if self.types_frozen {
+                    coerce_to_frozen(self.column_types[index], field)
+                } else {
+                    classify_and_widen(&mut self.column_types[index], field)
+                }
+            })
+            .collect();
+
+        if !self.types_frozen {
+            self.rows_sampled += 1;
+            if sample_window_closed(self.rows_sampled, self.sample_size) {
+                self.types_frozen = true;
+            }
+        }
+
+        cells
+    }
+
+    /// Wrap classified cells into the shape the caller asked for. Falls
+    /// back to a positional array when `keyed_rows` is off or no header row
+    /// has been seen yet (e.g. `has_headers` is `false`).
+    fn to_row_output(&self, cells: Vec<CellValue>) -> RowOutput {
+        let headers = match (self.keyed_rows, &self.normalized_headers) {
+            (true, Some(headers)) => headers,
+            _ => return RowOutput::Positional(cells),
+        };
+
+        let mut entries = Vec::with_capacity(headers.len().max(cells.len()));
+        let mut cells = cells.into_iter();
+
+        for header in headers {
+            entries.push((header.clone(), cells.next().unwrap_or(CellValue::Null)));
+        }
+
+        // A flexible row can run longer than the header; keep the extra
+        // fields instead of silently dropping them.
+        for (extra_index, value) in cells.enumerate() {
+            entries.push((format!("__extra_{extra_index}"), value));
+        }
+
+        RowOutput::Keyed(KeyedRow(entries))
+    }
 }
 
 /// Process a ReadableStream of CSV data with streaming support.
@@ -175,14 +715,14 @@ impl CsvSession {
 pub fn stream_csv(
     input: JsValue,
     progress_callback: Function,
-    delimiter: u8,
+    options: JsValue,
     has_headers: bool,
 ) -> Result<js_sys::Promise, JsValue> {
     // `future_to_promise` bridges the async Rust future into a JS `Promise`
     // so callers in TypeScript can `await` the stream piping operation just
     // like any other asynchronous browser API.
     Ok(future_to_promise(async move {
-        let mut session = CsvSession::new(delimiter, has_headers);
+        let mut session = CsvSession::new(options, has_headers)?;
         let mut input_stream = WasmReadableStream::from_raw(input.into()).into_stream();
         let callback = progress_callback;
 
@@ -192,19 +732,34 @@ pub fn stream_csv(
             let mut data = vec![0u8; view.length() as usize];
             view.copy_to(&mut data[..]);
 
-            // Feed bytes into the session and report the processed row count.
-            let rows = session.push_bytes(&data)?;
-            if !rows.is_empty() {
-                let count = JsValue::from_f64(rows.len() as f64);
-                callback.call1(&JsValue::NULL, &count)?;
+            // Feed bytes into the session and report each batch's row count,
+            // draining every batch the chunk produced before awaiting more
+            // input so peak memory stays bounded by `batch_size`.
+            let mut rows = session.push_bytes(&data)?;
+            loop {
+                if !rows.is_empty() {
+                    let count = JsValue::from_f64(rows.len() as f64);
+                    callback.call1(&JsValue::NULL, &count)?;
+                }
+                if !session.has_more() {
+                    break;
+                }
+                rows = session.next_batch_rows()?;
             }
         }
 
-        // Flush tail bytes after the stream ends.
-        let remaining = session.finish_rows()?;
-        if !remaining.is_empty() {
-            let count = JsValue::from_f64(remaining.len() as f64);
-            callback.call1(&JsValue::NULL, &count)?;
+        // Flush tail bytes after the stream ends, again draining every
+        // remaining batch.
+        let mut remaining = session.finish_rows()?;
+        loop {
+            if !remaining.is_empty() {
+                let count = JsValue::from_f64(remaining.len() as f64);
+                callback.call1(&JsValue::NULL, &count)?;
+            }
+            if !session.has_more() {
+                break;
+            }
+            remaining = session.next_batch_rows()?;
         }
 
         Ok(JsValue::undefined())
@@ -213,28 +768,60 @@ pub fn stream_csv(
 
 /// Process chunks of CSV data iteratively (Safari fallback).
 #[wasm_bindgen]
-pub fn process_csv_chunks(
-    delimiter: u8,
-    has_headers: bool,
-) -> CsvSession {
-    CsvSession::new(delimiter, has_headers)
+pub fn process_csv_chunks(options: JsValue, has_headers: bool) -> Result<CsvSession, JsValue> {
+    CsvSession::new(options, has_headers)
 }
 
 // Helper functions
 
-fn build_row(record: &[u8], field_ends: &[usize]) -> Result<Vec<String>, JsValue> {
+/// Decode a raw `csv-core` record into UTF-8 fields.
+///
+/// On success (or, in `lossy` mode, on a patched-up decode) this returns the
+/// row alongside any per-field decode errors. In non-lossy mode the first
+/// invalid field aborts immediately with a structured [`ParseError`] instead
+/// of an opaque string, positioned by `record_index`/field index so callers
+/// can tell exactly where the stream broke.
+fn build_row(
+    record: &[u8],
+    field_ends: &[usize],
+    record_index: usize,
+    byte_offset: u64,
+    lossy: bool,
+) -> Result<(Vec<String>, Vec<ParseError>), ParseError> {
     let mut row = Vec::new();
+    let mut errors = Vec::new();
     let mut start = 0;
 
-    for &end in field_ends {
+    for (field_index, &end) in field_ends.iter().enumerate() {
         let field_bytes = &record[start..end];
-        let field_str = std::str::from_utf8(field_bytes)
-            .map_err(|e| JsValue::from_str(&format!("UTF-8 error: {}", e)))?;
-        row.push(field_str.to_string());
+        match std::str::from_utf8(field_bytes) {
+            Ok(field_str) => row.push(field_str.to_string()),
+            Err(error) => {
+                let parse_error = ParseError {
+                    kind: "utf8",
+                    record: record_index,
+                    field: Some(field_index),
+                    byte_offset,
+                    message: error.to_string(),
+                    expected_fields: None,
+                    found_fields: None,
+                };
+
+                if !lossy {
+                    // Returned as a value rather than thrown here, so the
+                    // caller can run its own fatal-error bookkeeping cleanup
+                    // before surfacing this to JS — see `drain_records`.
+                    return Err(parse_error);
+                }
+
+                row.push(String::from_utf8_lossy(field_bytes).into_owned());
+                errors.push(parse_error);
+            }
+        }
         start = end;
     }
 
-    Ok(row)
+    Ok((row, errors))
 }
 
 fn normalize_field_name(field: &str) -> String {
@@ -246,12 +833,212 @@ fn normalize_field_name(field: &str) -> String {
         .collect()
 }
 
+/// Disambiguate header names that normalize to the same key (e.g. `"Name"`
+/// and `"name "`) by suffixing repeats with their occurrence count, so
+/// `KeyedRow` never has two fields silently clobber each other under one
+/// key.
+fn dedupe_header_names(names: Vec<String>) -> Vec<String> {
+    let mut used: std::collections::HashSet<String> = std::collections::HashSet::new();
+    names
+        .into_iter()
+        .map(|name| {
+            if used.insert(name.clone()) {
+                return name;
+            }
+            // Keep incrementing the suffix until it lands on a name nothing
+            // else has claimed yet, so a suffixed name can't itself collide
+            // with another header (e.g. "Name", "Name 2", "Name").
+            let mut suffix = 2;
+            loop {
+                let candidate = format!("{name}_{suffix}");
+                if used.insert(candidate.clone()) {
+                    return candidate;
+                }
+                suffix += 1;
+            }
+        })
+        .collect()
+}
+
 fn summarize_row(row: &[String]) -> String {
     // Check if row has any non-empty content
     row.iter().find(|s| !s.trim().is_empty()).map_or(String::new(), |_| "non-empty".to_string())
 }
 
-fn rows_to_js_value(rows: Vec<Vec<String>>) -> Result<JsValue, JsValue> {
-    // Convert the Rust vectors into a JS array-of-arrays.
+fn rows_to_js_value(rows: Vec<RowResult>) -> Result<JsValue, JsValue> {
+    // Convert the Rust rows into a JS array of arrays (or header-keyed
+    // objects when `keyed_rows` is on). Cells serialise as numbers,
+    // booleans, strings, or null depending on their inferred type. Rows
+    // patched up under `lossy` decoding carry a `{ row, errors }` wrapper
+    // instead of the bare row shape.
     to_value(&rows).map_err(|error| JsValue::from_str(&format!("Serialisation error: {error}")))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_window_closes_at_exactly_sample_size() {
+        assert!(!sample_window_closed(9, 10));
+        assert!(sample_window_closed(10, 10));
+        assert!(sample_window_closed(11, 10));
+    }
+
+    #[test]
+    fn column_widens_across_every_candidate_type() {
+        let mut candidate = CandidateType::Boolean;
+
+        assert!(matches!(
+            classify_and_widen(&mut candidate, "true"),
+            CellValue::Bool(true)
+        ));
+        assert_eq!(candidate, CandidateType::Boolean);
+
+        assert!(matches!(
+            classify_and_widen(&mut candidate, "42"),
+            CellValue::Number(n) if n == 42.0
+        ));
+        assert_eq!(candidate, CandidateType::Int64);
+
+        assert!(matches!(
+            classify_and_widen(&mut candidate, "3.14"),
+            CellValue::Number(n) if n == 3.14
+        ));
+        assert_eq!(candidate, CandidateType::Float64);
+
+        assert!(matches!(
+            classify_and_widen(&mut candidate, "2024-01-01"),
+            CellValue::Text(ref s) if s == "2024-01-01"
+        ));
+        assert_eq!(candidate, CandidateType::Date);
+
+        assert!(matches!(
+            classify_and_widen(&mut candidate, "hello world"),
+            CellValue::Text(ref s) if s == "hello world"
+        ));
+        assert_eq!(candidate, CandidateType::Utf8);
+    }
+
+    #[test]
+    fn frozen_column_falls_back_to_string_for_values_that_no_longer_fit() {
+        assert!(matches!(
+            coerce_to_frozen(CandidateType::Int64, "42"),
+            CellValue::Number(n) if n == 42.0
+        ));
+        assert!(matches!(
+            coerce_to_frozen(CandidateType::Int64, "not a number"),
+            CellValue::Text(ref s) if s == "not a number"
+        ));
+        assert!(matches!(coerce_to_frozen(CandidateType::Int64, ""), CellValue::Null));
+    }
+
+    #[test]
+    fn dedupe_header_names_suffixes_exact_duplicates() {
+        let result = dedupe_header_names(vec!["name".into(), "age".into(), "name".into()]);
+        assert_eq!(result, vec!["name", "age", "name_2"]);
+    }
+
+    #[test]
+    fn dedupe_header_names_avoids_colliding_with_an_existing_suffixed_name() {
+        // "name" repeats a third time after "name_2" is already taken, so
+        // the third occurrence must skip straight to "name_3" rather than
+        // clobbering the header that's already using "name_2".
+        let result = dedupe_header_names(vec!["name".into(), "name_2".into(), "name".into()]);
+        assert_eq!(result, vec!["name", "name_2", "name_3"]);
+    }
+
+    #[test]
+    fn build_row_aborts_on_invalid_utf8_when_not_lossy() {
+        let record = [b'a', 0xFF, b'b'];
+        let field_ends = [1usize, 3usize];
+
+        let error = build_row(&record, &field_ends, 0, 0, false)
+            .expect_err("invalid UTF-8 should abort when lossy is off");
+
+        assert_eq!(error.kind, "utf8");
+        assert_eq!(error.field, Some(1));
+    }
+
+    #[test]
+    fn build_row_patches_invalid_utf8_when_lossy() {
+        let record = [b'a', 0xFF, b'b'];
+        let field_ends = [1usize, 3usize];
+
+        let (row, errors) = build_row(&record, &field_ends, 0, 0, true)
+            .expect("lossy mode should patch the row up instead of aborting");
+
+        assert_eq!(row[0], "a");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, "utf8");
+        assert_eq!(errors[0].field, Some(1));
+    }
+
+    /// A bare-bones session for exercising `to_row_output` directly,
+    /// without going through `CsvSession::new`'s `JsValue` options.
+    fn keyed_session(headers: Vec<&str>) -> CsvSession {
+        CsvSession {
+            reader: ReaderBuilder::new().build(),
+            buffer: Vec::new(),
+            record_buffer: vec![0; 1024],
+            field_ends: vec![0; 32],
+            pending_record: Vec::new(),
+            pending_field_ends: Vec::new(),
+            has_headers: true,
+            headers_skipped: true,
+            normalized_headers: Some(headers.into_iter().map(String::from).collect()),
+            infer_types: false,
+            sample_size: 1000,
+            rows_sampled: 0,
+            types_frozen: false,
+            column_types: Vec::new(),
+            batch_size: 0,
+            finishing: false,
+            more_pending: false,
+            staging: Vec::new(),
+            keyed_rows: true,
+            flexible: true,
+            expected_field_count: None,
+            lossy: false,
+            record_index: 0,
+            bytes_consumed: 0,
+        }
+    }
+
+    #[test]
+    fn to_row_output_pads_a_short_ragged_row_with_null() {
+        let session = keyed_session(vec!["a", "b", "c"]);
+
+        let row_output = session.to_row_output(vec![CellValue::Text("1".to_string())]);
+
+        match row_output {
+            RowOutput::Keyed(KeyedRow(entries)) => {
+                assert_eq!(entries.len(), 3);
+                assert_eq!(entries[0].0, "a");
+                assert!(matches!(entries[1].1, CellValue::Null));
+                assert!(matches!(entries[2].1, CellValue::Null));
+            }
+            RowOutput::Positional(_) => panic!("expected a keyed row"),
+        }
+    }
+
+    #[test]
+    fn to_row_output_keeps_extra_fields_from_a_long_ragged_row() {
+        let session = keyed_session(vec!["a", "b"]);
+
+        let row_output = session.to_row_output(vec![
+            CellValue::Text("1".to_string()),
+            CellValue::Text("2".to_string()),
+            CellValue::Text("3".to_string()),
+        ]);
+
+        match row_output {
+            RowOutput::Keyed(KeyedRow(entries)) => {
+                assert_eq!(entries.len(), 3);
+                assert_eq!(entries[2].0, "__extra_0");
+                assert!(matches!(entries[2].1, CellValue::Text(ref s) if s == "3"));
+            }
+            RowOutput::Positional(_) => panic!("expected a keyed row"),
+        }
+    }
+}